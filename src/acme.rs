@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use acme_micro::{create_p384_key, Certificate, Directory, DirectoryUrl};
+use hyper::{Body, Request, Response};
+use rustls::sign::CertifiedKey;
+
+use crate::tls_reload;
+use piping_server::req_res_handler::ResSender;
+use piping_server::util;
+
+/// Renew a certificate once it is within this many days of expiry.
+const RENEW_WITHIN: chrono::Duration = chrono::Duration::days(30);
+/// How often the renewal loop wakes up to check certificate expiry.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60 * 12);
+
+/// How long `acme_micro` sleeps between re-checking a challenge's or
+/// order's status while it's pending. This is a poll cadence, not a
+/// deadline: `Challenge::validate` and `CertificateSigner::finalize_pkey`
+/// both loop internally, re-querying the ACME server on this interval
+/// until it reports the final status (or its own internal retry budget
+/// is exhausted), so a short value here doesn't risk spurious failures
+/// against Let's Encrypt's real-world validation latency — it only
+/// controls how promptly we notice a status change. 5s matches the
+/// interval used in acme_micro's own examples.
+const ACME_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Key authorizations for in-flight HTTP-01 challenges, keyed by
+/// token. Populated while an order is being validated and consulted
+/// by the plain-HTTP handler to answer
+/// `GET /.well-known/acme-challenge/<token>`.
+#[derive(Clone, Default)]
+pub struct ChallengeStore {
+    tokens: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl ChallengeStore {
+    pub fn get(&self, token: &str) -> Option<String> {
+        self.tokens.lock().unwrap().get(token).cloned()
+    }
+
+    fn insert(&self, token: String, key_authorization: String) {
+        self.tokens.lock().unwrap().insert(token, key_authorization);
+    }
+
+    fn remove(&self, token: &str) {
+        self.tokens.lock().unwrap().remove(token);
+    }
+}
+
+/// Drives ACME account registration, order creation, HTTP-01 challenge
+/// fulfilment and certificate renewal for a fixed set of domains,
+/// feeding newly issued certs into a [`tls_reload::CertReloader`].
+pub struct AcmeManager {
+    domains: Vec<String>,
+    email: String,
+    crt_path: String,
+    key_path: String,
+    challenges: ChallengeStore,
+    reloader: Arc<tls_reload::CertReloader>,
+}
+
+impl AcmeManager {
+    pub fn new(
+        domains: Vec<String>,
+        email: String,
+        crt_path: String,
+        key_path: String,
+        challenges: ChallengeStore,
+        reloader: Arc<tls_reload::CertReloader>,
+    ) -> Arc<AcmeManager> {
+        Arc::new(AcmeManager {
+            domains,
+            email,
+            crt_path,
+            key_path,
+            challenges,
+            reloader,
+        })
+    }
+
+    /// Spawn the background renewal loop. Issues a certificate
+    /// immediately if one is not already on disk, then checks
+    /// periodically and renews within [`RENEW_WITHIN`] of expiry.
+    pub fn spawn(self: Arc<Self>) {
+        tokio::spawn(async move {
+            loop {
+                match self.renew_if_needed().await {
+                    Ok(true) => log::info!("ACME certificate issued/renewed for {:?}", self.domains),
+                    Ok(false) => {}
+                    Err(e) => log::error!("ACME renewal failed: {}", e),
+                }
+                tokio::time::sleep(CHECK_INTERVAL).await;
+            }
+        });
+    }
+
+    fn needs_renewal(&self) -> bool {
+        match tls_reload::load_certified_key(&self.crt_path, &self.key_path) {
+            Err(_) => true,
+            Ok(certified_key) => cert_expires_within(&certified_key, RENEW_WITHIN),
+        }
+    }
+
+    async fn renew_if_needed(&self) -> std::io::Result<bool> {
+        if !self.needs_renewal() {
+            return Ok(false);
+        }
+
+        let domains = self.domains.clone();
+        let email = self.email.clone();
+        let challenges = self.challenges.clone();
+        let (crt_pem, key_pem) = tokio::task::spawn_blocking(move || {
+            order_certificate(&domains, &email, &challenges)
+        })
+        .await
+        .map_err(|e| util::make_io_error(format!("ACME task panicked: {}", e)))??;
+
+        std::fs::write(&self.crt_path, &crt_pem)?;
+        write_private_key(&self.key_path, &key_pem)?;
+
+        let certified_key = tls_reload::load_certified_key(&self.crt_path, &self.key_path)?;
+        self.reloader.store(certified_key);
+        Ok(true)
+    }
+}
+
+/// Whether the leaf certificate in `certified_key` expires within
+/// `within` of now.
+fn cert_expires_within(certified_key: &CertifiedKey, within: chrono::Duration) -> bool {
+    let leaf = match certified_key.cert.first() {
+        Some(leaf) => leaf,
+        None => return true,
+    };
+    let (_, x509) = match x509_parser::parse_x509_certificate(&leaf.0) {
+        Ok(parsed) => parsed,
+        Err(_) => return true,
+    };
+    let not_after = x509.validity().not_after.timestamp();
+    let deadline = chrono::Utc::now() + within;
+    not_after <= deadline.timestamp()
+}
+
+/// Provision the very first certificate for `domains` synchronously,
+/// writing it to `crt_path`/`key_path` and returning the resulting
+/// [`CertifiedKey`] so the caller can start serving immediately.
+pub fn provision_certificate_sync(
+    domains: &[String],
+    email: &str,
+    crt_path: &str,
+    key_path: &str,
+    challenges: &ChallengeStore,
+) -> std::io::Result<CertifiedKey> {
+    let (crt_pem, key_pem) = order_certificate(domains, email, challenges)?;
+    std::fs::write(crt_path, &crt_pem)?;
+    write_private_key(key_path, &key_pem)?;
+    tls_reload::load_certified_key(crt_path, key_path)
+}
+
+/// Write a freshly issued private key to `path` with `0600`
+/// permissions, rather than taking the process umask (commonly
+/// `0644`) and leaving key material group/world-readable.
+#[cfg(unix)]
+fn write_private_key(path: &str, key_pem: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(key_pem.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_private_key(path: &str, key_pem: &str) -> std::io::Result<()> {
+    std::fs::write(path, key_pem)
+}
+
+/// Answer `GET /.well-known/acme-challenge/<token>` with the matching
+/// key authorization when one is pending in `challenges`, otherwise
+/// fall through to `fallback`. Intended to wrap the plain HTTP
+/// `PipingServer::handler` so ACME validation requests never reach it.
+pub async fn serve_challenge_or<F, Fut>(
+    challenges: &ChallengeStore,
+    req: Request<Body>,
+    res_sender: ResSender,
+    fallback: F,
+) where
+    F: FnOnce(Request<Body>, ResSender) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let token = req
+        .uri()
+        .path()
+        .strip_prefix("/.well-known/acme-challenge/")
+        .and_then(|token| challenges.get(token));
+    match token {
+        Some(key_authorization) => {
+            let _ = res_sender.send(Ok(Response::new(Body::from(key_authorization))));
+        }
+        None => fallback(req, res_sender).await,
+    }
+}
+
+/// Run the synchronous acme-micro flow: register a new account
+/// (no existing account is loaded), create an order for `domains`, answer each HTTP-01
+/// challenge via `challenges`, wait for validation, then finalize and
+/// download the issued certificate chain and private key (both PEM).
+fn order_certificate(
+    domains: &[String],
+    email: &str,
+    challenges: &ChallengeStore,
+) -> std::io::Result<(String, String)> {
+    let dir = Directory::from_url(DirectoryUrl::LetsEncrypt)
+        .map_err(|e| util::make_io_error(format!("Failed to reach ACME directory: {}", e)))?;
+    let account = dir
+        .register_account(Some(vec![format!("mailto:{}", email)]))
+        .map_err(|e| util::make_io_error(format!("ACME account registration failed: {}", e)))?;
+
+    let mut order = account
+        .new_order(domains, &[])
+        .map_err(|e| util::make_io_error(format!("Failed to create ACME order: {}", e)))?;
+
+    let order_csr = loop {
+        if let Some(order_csr) = order.confirm_validations() {
+            break order_csr;
+        }
+
+        let auths = order
+            .authorizations()
+            .map_err(|e| util::make_io_error(format!("Failed to fetch authorizations: {}", e)))?;
+        for auth in auths {
+            let challenge = auth.http_challenge();
+            let token = challenge.http_token().to_owned();
+            let key_authorization = challenge.http_proof();
+            challenges.insert(token.clone(), key_authorization);
+
+            challenge
+                .validate(ACME_POLL_INTERVAL)
+                .map_err(|e| util::make_io_error(format!("Challenge validation failed: {}", e)))?;
+            challenges.remove(&token);
+        }
+
+        order
+            .refresh()
+            .map_err(|e| util::make_io_error(format!("Failed to refresh order: {}", e)))?;
+    };
+
+    let private_key = create_p384_key();
+    let cert: Certificate = order_csr
+        .finalize_pkey(private_key, ACME_POLL_INTERVAL)
+        .and_then(|csr| csr.download_cert())
+        .map_err(|e| util::make_io_error(format!("Failed to finalize ACME order: {}", e)))?;
+
+    Ok((cert.certificate().to_owned(), cert.private_key().to_owned()))
+}
@@ -3,13 +3,27 @@ use futures::stream::{StreamExt, TryStreamExt};
 use hyper::service::{make_service_fn, service_fn};
 use hyper::Server;
 use structopt::StructOpt;
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream, UnixListener};
 use tokio_rustls::TlsAcceptor;
 
 use piping_server::piping_server::PipingServer;
 use piping_server::req_res_handler::req_res_handler;
 use piping_server::util;
 
+use conn_stream::ConnStream;
+
+mod acme;
+mod conn_stream;
+mod proxy_protocol;
+mod tls_reload;
+
+/// Either the TCP listener or the Unix domain socket listener that
+/// the plain HTTP server accepts connections from.
+enum HttpListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
 /// Piping Server in Rust
 #[derive(StructOpt, Debug)]
 #[structopt(name = "piping-server")]
@@ -30,6 +44,104 @@ struct Opt {
     /// Private key path
     #[structopt(long)]
     key_path: Option<String>,
+    /// Watch the cert/key files and reload them without restarting
+    #[structopt(long)]
+    tls_watch: bool,
+    /// Domains to provision an HTTPS certificate for automatically via ACME (Let's Encrypt)
+    #[structopt(long, use_delimiter = true)]
+    acme_domains: Vec<String>,
+    /// Contact email used for ACME account registration
+    #[structopt(long)]
+    acme_email: Option<String>,
+    /// Advertise HTTP/2 over ALPN and allow multiplexed pipes on one connection
+    #[structopt(long)]
+    http2: bool,
+    /// Seconds to wait for in-flight transfers to finish after a shutdown signal before forcing exit
+    #[structopt(long, default_value = "30")]
+    shutdown_timeout: u64,
+    /// Expect a PROXY protocol (v1 or v2) header on each accepted connection
+    #[structopt(long)]
+    proxy_protocol: bool,
+    /// Listen on a Unix domain socket instead of a TCP port for plain HTTP
+    #[structopt(long)]
+    unix_socket: Option<String>,
+}
+
+/// Advertise `h2` (ahead of `http/1.1`) in the TLS ALPN extension so
+/// clients that support HTTP/2 can negotiate it, letting a single
+/// connection carry several concurrent pipe streams.
+fn enable_h2_alpn(tls_cfg: &mut rustls::ServerConfig) {
+    tls_cfg.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+}
+
+/// If `enabled`, read and strip a leading PROXY protocol header off
+/// `stream`, logging the client address it carried; otherwise pass
+/// the stream through untouched. A malformed header closes the
+/// connection by propagating the error to the caller.
+async fn strip_proxy_header(mut stream: TcpStream, enabled: bool) -> std::io::Result<TcpStream> {
+    if !enabled {
+        return Ok(stream);
+    }
+    let header = proxy_protocol::read_header(&mut stream).await?;
+    if let Some(source) = header.source {
+        log::info!("PROXY protocol recovered client address: {}", source);
+    }
+    Ok(stream)
+}
+
+/// Resolves once `shutdown_rx` observes `true`, i.e. once a shutdown
+/// signal has been received. Passed to `with_graceful_shutdown` so
+/// listeners stop accepting new connections but let in-flight pipe
+/// transfers finish.
+async fn wait_for_shutdown(mut shutdown_rx: tokio::sync::watch::Receiver<bool>) {
+    while !*shutdown_rx.borrow() {
+        if shutdown_rx.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Wait for Ctrl-C or, on Unix, SIGTERM, then notify `shutdown_tx` so
+/// graceful shutdown begins. If the process hasn't exited on its own
+/// within `shutdown_timeout` afterwards, force it closed so deploys
+/// don't hang on a stuck connection. `unix_socket_path`, if set, is
+/// removed before the forced exit, since that cleanup would otherwise
+/// only happen after the servers return from the normal shutdown path
+/// further down in `main`, which a forced exit never reaches.
+fn spawn_shutdown_listener(
+    shutdown_tx: tokio::sync::watch::Sender<bool>,
+    shutdown_timeout: u64,
+    unix_socket_path: Option<String>,
+) {
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        {
+            let mut sigterm =
+                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                    .expect("Failed to install SIGTERM handler");
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {},
+                _ = sigterm.recv() => {},
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = tokio::signal::ctrl_c().await;
+        }
+
+        log::info!("Shutdown signal received, draining existing connections...");
+        let _ = shutdown_tx.send(true);
+
+        tokio::time::sleep(std::time::Duration::from_secs(shutdown_timeout)).await;
+        log::warn!(
+            "Shutdown timeout of {}s elapsed, forcing exit",
+            shutdown_timeout
+        );
+        if let Some(path) = &unix_socket_path {
+            let _ = std::fs::remove_file(path);
+        }
+        std::process::exit(0);
+    });
 }
 
 #[tokio::main]
@@ -37,6 +149,14 @@ async fn main() -> std::io::Result<()> {
     // Parse options
     let opt = Opt::from_args();
 
+    if opt.unix_socket.is_some() && !opt.acme_domains.is_empty() {
+        return Err(util::make_io_error(
+            "--unix-socket cannot be combined with --acme-domains: Let's Encrypt's HTTP-01 \
+             validator needs a reachable TCP port to issue or renew the certificate"
+                .to_owned(),
+        ));
+    }
+
     let mut tcp: TcpListener;
     let tls_acceptor: TlsAcceptor;
 
@@ -45,21 +165,238 @@ async fn main() -> std::io::Result<()> {
     // Set default log level
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
+    // Key authorizations for in-flight ACME HTTP-01 challenges. Always
+    // created, even when ACME is unused, so the plain HTTP handler
+    // below can unconditionally consult it.
+    let acme_challenges = acme::ChallengeStore::default();
+
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    spawn_shutdown_listener(shutdown_tx, opt.shutdown_timeout, opt.unix_socket.clone());
+
+    // Bind and start serving plain HTTP before touching ACME below: an
+    // `--acme-domains` first run issues its initial certificate by
+    // having Let's Encrypt hit this listener's HTTP-01 responder, so
+    // it must already be accepting connections by then.
+    let http_acme_challenges = acme_challenges.clone();
+    let http_svc = make_service_fn(move |_| {
+        let piping_server = piping_server.clone();
+        let acme_challenges = http_acme_challenges.clone();
+        let handler = req_res_handler(move |req, res_sender| {
+            acme::serve_challenge_or(&acme_challenges, req, res_sender, |req, res_sender| {
+                piping_server.handler(false, req, res_sender)
+            })
+        });
+        futures::future::ok::<_, Infallible>(service_fn(handler))
+    });
+    let http_listener = match &opt.unix_socket {
+        Some(path) => {
+            // A stale socket file from an unclean shutdown would
+            // otherwise make bind() fail with "address in use".
+            let _ = std::fs::remove_file(path);
+            HttpListener::Unix(UnixListener::bind(path)?)
+        }
+        None => HttpListener::Tcp(TcpListener::bind(&([0, 0, 0, 0], opt.http_port).into()).await?),
+    };
+    let proxy_protocol = opt.proxy_protocol;
+    let incoming_conn_stream = futures::stream::unfold(http_listener, move |listener| async move {
+        loop {
+            let accepted = match &listener {
+                HttpListener::Tcp(l) => l.accept().await.map(|(s, _)| ConnStream::Tcp(s)),
+                HttpListener::Unix(l) => l.accept().await.map(|(s, _)| ConnStream::Unix(s)),
+            };
+            let conn = match accepted {
+                Ok(conn) => conn,
+                Err(e) => {
+                    log::error!("Failed to accept client: {}", e);
+                    continue;
+                }
+            };
+            let conn = match conn {
+                ConnStream::Tcp(tcp) => match strip_proxy_header(tcp, proxy_protocol).await {
+                    Ok(tcp) => ConnStream::Tcp(tcp),
+                    Err(e) => {
+                        log::error!("Invalid PROXY protocol header: {}", e);
+                        continue;
+                    }
+                },
+                unix @ ConnStream::Unix(_) => unix,
+            };
+            return Some((Ok::<_, std::io::Error>(conn), listener));
+        }
+    });
+    let http_server = Server::builder(util::HyperAcceptor {
+        acceptor: incoming_conn_stream,
+    })
+    // The cleartext listener has no ALPN to negotiate over, so a
+    // client speaking HTTP/2 with prior knowledge would otherwise get
+    // multiplexed h2c regardless of `--http2`; `http1_only` is what
+    // actually gates HTTP/2 on this plain-HTTP listener.
+    .http1_only(!opt.http2)
+    .http2_adaptive_window(opt.http2)
+    .serve(http_svc)
+    .with_graceful_shutdown(wait_for_shutdown(shutdown_rx.clone()));
+    tokio::pin!(http_server);
+
+    if let Some(path) = &opt.unix_socket {
+        log::info!("HTTP server is running on unix socket {}...", path);
+    } else {
+        log::info!("HTTP server is running on {}...", opt.http_port);
+    }
+
     let https_server = if opt.enable_https {
-        if let (Some(https_port), Some(crt_path), Some(key_path)) =
+        if !opt.acme_domains.is_empty() {
+            let acme_email = opt.acme_email.clone().ok_or_else(|| {
+                util::make_io_error(
+                    "--acme-email should be specified with --acme-domains".to_owned(),
+                )
+            })?;
+            let https_port = opt.https_port.ok_or_else(|| {
+                util::make_io_error("--https-port should be specified".to_owned())
+            })?;
+            let crt_path = opt
+                .crt_path
+                .clone()
+                .unwrap_or_else(|| "acme_cert.pem".to_owned());
+            let key_path = opt
+                .key_path
+                .clone()
+                .unwrap_or_else(|| "acme_key.pem".to_owned());
+
+            // Provision a certificate on first run so the listener has
+            // something to serve immediately; after that the manager's
+            // background task keeps it renewed. The blocking acme-micro
+            // flow runs on its own thread (like `renew_if_needed` does)
+            // while the plain HTTP listener above keeps being polled,
+            // since Let's Encrypt validates the HTTP-01 challenge by
+            // calling back into it.
+            let certified_key = match tls_reload::load_certified_key(&crt_path, &key_path) {
+                Ok(certified_key) => certified_key,
+                Err(_) => {
+                    let domains = opt.acme_domains.clone();
+                    let email = acme_email.clone();
+                    let crt_path = crt_path.clone();
+                    let key_path = key_path.clone();
+                    let challenges = acme_challenges.clone();
+                    let provision_fut = tokio::task::spawn_blocking(move || {
+                        acme::provision_certificate_sync(
+                            &domains,
+                            &email,
+                            &crt_path,
+                            &key_path,
+                            &challenges,
+                        )
+                    });
+                    tokio::pin!(provision_fut);
+                    loop {
+                        tokio::select! {
+                            result = &mut provision_fut => {
+                                break result
+                                    .map_err(|e| util::make_io_error(format!("ACME task panicked: {}", e)))??;
+                            }
+                            _ = &mut http_server => {
+                                return Err(util::make_io_error(
+                                    "Plain HTTP listener exited while provisioning the initial ACME certificate".to_owned(),
+                                ));
+                            }
+                        }
+                    }
+                }
+            };
+            let reloader = tls_reload::CertReloader::new(certified_key);
+            acme::AcmeManager::new(
+                opt.acme_domains.clone(),
+                acme_email,
+                crt_path,
+                key_path,
+                acme_challenges.clone(),
+                reloader.clone(),
+            )
+            .spawn();
+
+            let mut tls_cfg = rustls::ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_cert_resolver(reloader);
+            if opt.http2 {
+                enable_h2_alpn(&mut tls_cfg);
+            }
+
+            let addr: std::net::SocketAddr = ([0, 0, 0, 0], https_port).into();
+            tcp = TcpListener::bind(&addr).await?;
+            tls_acceptor = TlsAcceptor::from(std::sync::Arc::new(tls_cfg));
+            let proxy_protocol = opt.proxy_protocol;
+            let incoming_tls_stream = util::TokioIncoming::new(&mut tcp)
+                .map_err(|e| util::make_io_error(format!("Incoming failed: {:?}", e)))
+                .filter_map(move |s| async move {
+                    let client = match s {
+                        Ok(x) => x,
+                        Err(e) => {
+                            log::error!("Failed to accept client: {}", e);
+                            return None;
+                        }
+                    };
+                    let client = match strip_proxy_header(client, proxy_protocol).await {
+                        Ok(x) => x,
+                        Err(e) => {
+                            log::error!("Invalid PROXY protocol header: {}", e);
+                            return None;
+                        }
+                    };
+                    match tls_acceptor.accept(client).await {
+                        Ok(x) => Some(Ok::<_, std::io::Error>(x)),
+                        Err(e) => {
+                            log::error!("Client connection error: {}", e);
+                            None
+                        }
+                    }
+                });
+            let https_svc = make_service_fn(move |_| {
+                let piping_server = piping_server.clone();
+                let handler = req_res_handler(move |req, res_sender| {
+                    piping_server.handler(true, req, res_sender)
+                });
+                futures::future::ok::<_, Infallible>(service_fn(handler))
+            });
+            let https_server = Server::builder(util::HyperAcceptor {
+                acceptor: incoming_tls_stream,
+            })
+            // Belt and braces alongside the ALPN gating above: hyper
+            // still auto-senses an h2 client preface regardless of the
+            // negotiated protocol, so this is what actually stops a
+            // prior-knowledge HTTP/2 client when `--http2` is off.
+            .http1_only(!opt.http2)
+            .http2_adaptive_window(opt.http2)
+            .serve(https_svc)
+            .with_graceful_shutdown(wait_for_shutdown(shutdown_rx.clone()));
+            futures::future::Either::Left(https_server)
+        } else if let (Some(https_port), Some(crt_path), Some(key_path)) =
             (opt.https_port, opt.crt_path, opt.key_path)
         {
-            let tls_cfg = util::load_tls_config(crt_path, key_path)?;
+            let mut tls_cfg = if opt.tls_watch {
+                let certified_key = tls_reload::load_certified_key(&crt_path, &key_path)?;
+                let reloader = tls_reload::CertReloader::new(certified_key);
+                tls_reload::spawn_watcher(reloader.clone(), crt_path, key_path);
+                rustls::ServerConfig::builder()
+                    .with_safe_defaults()
+                    .with_no_client_auth()
+                    .with_cert_resolver(reloader)
+            } else {
+                util::load_tls_config(crt_path, key_path)?
+            };
+            if opt.http2 {
+                enable_h2_alpn(&mut tls_cfg);
+            }
 
             let addr: std::net::SocketAddr = ([0, 0, 0, 0], https_port).into();
             // Create a TCP listener via tokio.
             tcp = TcpListener::bind(&addr).await?;
             tls_acceptor = TlsAcceptor::from(std::sync::Arc::new(tls_cfg));
             // Prepare a long-running future stream to accept and serve clients.
+            let proxy_protocol = opt.proxy_protocol;
             let incoming_tls_stream = util::TokioIncoming::new(&mut tcp)
                 .map_err(|e| util::make_io_error(format!("Incoming failed: {:?}", e)))
                 // (base: https://github.com/cloudflare/wrangler/pull/1485/files)
-                .filter_map(|s| async {
+                .filter_map(move |s| async move {
                     let client = match s {
                         Ok(x) => x,
                         Err(e) => {
@@ -67,6 +404,13 @@ async fn main() -> std::io::Result<()> {
                             return None;
                         }
                     };
+                    let client = match strip_proxy_header(client, proxy_protocol).await {
+                        Ok(x) => x,
+                        Err(e) => {
+                            log::error!("Invalid PROXY protocol header: {}", e);
+                            return None;
+                        }
+                    };
                     match tls_acceptor.accept(client).await {
                         Ok(x) => Some(Ok::<_, std::io::Error>(x)),
                         Err(e) => {
@@ -85,7 +429,14 @@ async fn main() -> std::io::Result<()> {
             let https_server = Server::builder(util::HyperAcceptor {
                 acceptor: incoming_tls_stream,
             })
-            .serve(https_svc);
+            // Belt and braces alongside the ALPN gating above: hyper
+            // still auto-senses an h2 client preface regardless of the
+            // negotiated protocol, so this is what actually stops a
+            // prior-knowledge HTTP/2 client when `--http2` is off.
+            .http1_only(!opt.http2)
+            .http2_adaptive_window(opt.http2)
+            .serve(https_svc)
+            .with_graceful_shutdown(wait_for_shutdown(shutdown_rx.clone()));
             futures::future::Either::Left(https_server)
         } else {
             return Err(util::make_io_error(
@@ -96,19 +447,14 @@ async fn main() -> std::io::Result<()> {
         futures::future::Either::Right(futures::future::ok(()))
     };
 
-    let http_svc = make_service_fn(|_| {
-        let piping_server = piping_server.clone();
-        let handler =
-            req_res_handler(move |req, res_sender| piping_server.handler(false, req, res_sender));
-        futures::future::ok::<_, Infallible>(service_fn(handler))
-    });
-    let http_server = Server::bind(&([0, 0, 0, 0], opt.http_port).into()).serve(http_svc);
-
-    log::info!("HTTP server is running on {}...", opt.http_port);
     if let Some(https_port) = opt.https_port {
         log::info!("HTTPS server is running on {:?}...", https_port);
     }
-    match futures::future::join(http_server, https_server).await {
+    let join_result = futures::future::join(http_server, https_server).await;
+    if let Some(path) = &opt.unix_socket {
+        let _ = std::fs::remove_file(path);
+    }
+    match join_result {
         (Err(e), _) => return Err(util::make_io_error(format!("HTTP server error: {}", e))),
         (_, Err(e)) => return Err(util::make_io_error(format!("HTTPS server error: {}", e))),
         _ => (),
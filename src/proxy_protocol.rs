@@ -0,0 +1,140 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use piping_server::util;
+
+/// Client address recovered from a PROXY protocol header. `None` for
+/// a v1 `UNKNOWN` connection or a v2 `LOCAL` command, both of which
+/// carry no address.
+#[derive(Debug, Clone, Copy)]
+pub struct ProxyHeader {
+    pub source: Option<SocketAddr>,
+}
+
+/// Longest possible PROXY protocol v1 header line, per the spec.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Read and strip a PROXY protocol header (v1 or v2) off the front of
+/// `stream`, returning the source address it carried. `stream` is
+/// left positioned exactly after the header, so the real payload
+/// (TLS ClientHello, HTTP request, ...) can be read normally by the
+/// caller. A malformed header is reported as an `InvalidData` error;
+/// callers should close the connection on error rather than read on.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<ProxyHeader> {
+    let mut prefix = [0u8; 12];
+    stream.read_exact(&mut prefix).await?;
+    if prefix == V2_SIGNATURE {
+        read_v2_header(stream).await
+    } else {
+        read_v1_header(stream, &prefix).await
+    }
+}
+
+async fn read_v1_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    prefix: &[u8],
+) -> std::io::Result<ProxyHeader> {
+    let mut line = prefix.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(util::make_io_error(
+                "PROXY protocol v1 header exceeds maximum length".to_owned(),
+            ));
+        }
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte).await?;
+        line.push(byte[0]);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| util::make_io_error("PROXY protocol v1 header is not valid UTF-8".to_owned()))?;
+
+    let mut parts = line.split(' ');
+    if parts.next() != Some("PROXY") {
+        return Err(util::make_io_error(
+            "PROXY protocol v1 header missing PROXY tag".to_owned(),
+        ));
+    }
+    match parts.next() {
+        Some("UNKNOWN") => Ok(ProxyHeader { source: None }),
+        Some("TCP4") | Some("TCP6") => {
+            let src_ip: IpAddr = parts
+                .next()
+                .ok_or_else(|| util::make_io_error("PROXY protocol v1 missing source address".to_owned()))?
+                .parse()
+                .map_err(|_| util::make_io_error("PROXY protocol v1 has an invalid source address".to_owned()))?;
+            let _dst_ip = parts.next();
+            let src_port: u16 = parts
+                .next()
+                .ok_or_else(|| util::make_io_error("PROXY protocol v1 missing source port".to_owned()))?
+                .parse()
+                .map_err(|_| util::make_io_error("PROXY protocol v1 has an invalid source port".to_owned()))?;
+            Ok(ProxyHeader {
+                source: Some(SocketAddr::new(src_ip, src_port)),
+            })
+        }
+        _ => Err(util::make_io_error(
+            "PROXY protocol v1 header has an unsupported protocol".to_owned(),
+        )),
+    }
+}
+
+async fn read_v2_header<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<ProxyHeader> {
+    let mut ver_cmd_fam = [0u8; 2];
+    stream.read_exact(&mut ver_cmd_fam).await?;
+    let version = ver_cmd_fam[0] >> 4;
+    if version != 2 {
+        return Err(util::make_io_error(format!(
+            "Unsupported PROXY protocol version: {}",
+            version
+        )));
+    }
+    let command = ver_cmd_fam[0] & 0x0f;
+    let family = ver_cmd_fam[1] >> 4;
+    let protocol = ver_cmd_fam[1] & 0x0f;
+
+    let mut len_buf = [0u8; 2];
+    stream.read_exact(&mut len_buf).await?;
+    let len = u16::from_be_bytes(len_buf) as usize;
+
+    let mut addr_block = vec![0u8; len];
+    stream.read_exact(&mut addr_block).await?;
+
+    // command 0x0 is LOCAL (e.g. a health check from the proxy itself):
+    // no address to recover, and protocol 0x0 means "unspecified".
+    if command == 0x0 || protocol == 0x0 {
+        return Ok(ProxyHeader { source: None });
+    }
+
+    let source = match family {
+        // AF_INET
+        0x1 => {
+            if addr_block.len() < 12 {
+                return Err(util::make_io_error(
+                    "PROXY protocol v2 address block too short for AF_INET".to_owned(),
+                ));
+            }
+            let ip = Ipv4Addr::new(addr_block[0], addr_block[1], addr_block[2], addr_block[3]);
+            let port = u16::from_be_bytes([addr_block[8], addr_block[9]]);
+            Some(SocketAddr::new(IpAddr::V4(ip), port))
+        }
+        // AF_INET6
+        0x2 => {
+            if addr_block.len() < 36 {
+                return Err(util::make_io_error(
+                    "PROXY protocol v2 address block too short for AF_INET6".to_owned(),
+                ));
+            }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addr_block[0..16]);
+            let ip = Ipv6Addr::from(octets);
+            let port = u16::from_be_bytes([addr_block[32], addr_block[33]]);
+            Some(SocketAddr::new(IpAddr::V6(ip), port))
+        }
+        // AF_UNIX or AF_UNSPEC carry no routable address we can use here.
+        _ => None,
+    };
+
+    Ok(ProxyHeader { source })
+}
@@ -0,0 +1,109 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+
+use piping_server::util;
+
+/// How often the watcher re-reads the cert/key files from disk.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A `ResolvesServerCert` whose `CertifiedKey` can be swapped out at
+/// runtime without tearing down existing connections.
+///
+/// Handshakes in flight keep the `CertifiedKey` they resolved; only
+/// new handshakes observe a refreshed cert.
+pub struct CertReloader {
+    current: ArcSwap<CertifiedKey>,
+}
+
+impl CertReloader {
+    pub fn new(certified_key: CertifiedKey) -> Arc<CertReloader> {
+        Arc::new(CertReloader {
+            current: ArcSwap::new(Arc::new(certified_key)),
+        })
+    }
+
+    /// Atomically replace the served certificate.
+    pub fn store(&self, certified_key: CertifiedKey) {
+        self.current.store(Arc::new(certified_key));
+    }
+}
+
+impl ResolvesServerCert for CertReloader {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.load_full())
+    }
+}
+
+/// Load a `CertifiedKey` from a PEM-encoded certificate chain and
+/// private key on disk. The key may be PKCS#8, traditional RSA (as
+/// produced by certbot and most ACME tooling), or SEC1 EC.
+pub fn load_certified_key(crt_path: &str, key_path: &str) -> std::io::Result<CertifiedKey> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(crt_path)?))
+        .map_err(|e| util::make_io_error(format!("Failed to read cert file: {:?}", e)))?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+    let key = read_private_key(key_path)?;
+    let signing_key = rustls::sign::any_supported_type(&key)
+        .map_err(|e| util::make_io_error(format!("Unsupported private key: {:?}", e)))?;
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+/// Read a PEM-encoded private key, trying PKCS#8, then traditional
+/// RSA, then SEC1 EC encodings in turn since `rustls_pemfile` parses
+/// each format separately and silently returns no keys (not an error)
+/// when the file holds a different one.
+fn read_private_key(key_path: &str) -> std::io::Result<rustls::PrivateKey> {
+    let read_err = |e| util::make_io_error(format!("Failed to read key file: {:?}", e));
+
+    let mut keys = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(key_path)?))
+        .map_err(read_err)?;
+    if keys.is_empty() {
+        keys = rustls_pemfile::rsa_private_keys(&mut BufReader::new(File::open(key_path)?))
+            .map_err(read_err)?;
+    }
+    if keys.is_empty() {
+        keys = rustls_pemfile::ec_private_keys(&mut BufReader::new(File::open(key_path)?))
+            .map_err(read_err)?;
+    }
+    keys.into_iter()
+        .next()
+        .map(rustls::PrivateKey)
+        .ok_or_else(|| util::make_io_error(format!("No private key found in {}", key_path)))
+}
+
+/// Spawn a background task that re-reads `crt_path`/`key_path` on a
+/// fixed interval and, if they parse successfully, stores the new
+/// `CertifiedKey` into `reloader`. Read errors are logged and the
+/// previous cert keeps serving.
+pub fn spawn_watcher(reloader: Arc<CertReloader>, crt_path: String, key_path: String) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        // The first tick fires immediately; skip it since we already
+        // loaded the initial cert before spawning this task.
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match load_certified_key(&crt_path, &key_path) {
+                Ok(certified_key) => {
+                    reloader.store(certified_key);
+                    log::info!(
+                        "Reloaded TLS certificate from {:?}/{:?}",
+                        PathBuf::from(&crt_path),
+                        PathBuf::from(&key_path)
+                    );
+                }
+                Err(e) => {
+                    log::error!("Failed to reload TLS certificate: {}", e);
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,108 @@
+//! Checks that `--http2` lets several pipes run concurrently over one
+//! multiplexed connection, and that leaving it off forces HTTP/1.1
+//! (one request in flight per connection) on the plain HTTP listener.
+//! The TLS listener is covered in `https_http2_multiplex.rs`.
+
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use hyper::{Body, Client, Request};
+
+struct ServerProcess(Child);
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn spawn_server(http_port: u16, http2: bool) -> ServerProcess {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_piping-server"));
+    cmd.arg("--http-port")
+        .arg(http_port.to_string())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    if http2 {
+        cmd.arg("--http2");
+    }
+    ServerProcess(cmd.spawn().expect("failed to spawn piping-server"))
+}
+
+async fn wait_for_listening(port: u16) {
+    for _ in 0..50 {
+        if tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .is_ok()
+        {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("server never started listening on port {}", port);
+}
+
+#[tokio::test]
+async fn multiplexes_several_pipes_over_one_http2_connection() {
+    let port = 18080;
+    let _server = spawn_server(port, true);
+    wait_for_listening(port).await;
+
+    let client = Client::builder()
+        .http2_only(true)
+        .build_http::<Body>();
+
+    // Start two GETs before either sender shows up: if they shared one
+    // multiplexed connection, both should be unblocked independently
+    // once their matching sender arrives, instead of the second
+    // waiting behind the first.
+    let get = |path: &'static str| {
+        let client = client.clone();
+        async move {
+            let uri = format!("http://127.0.0.1:{}/{}", port, path).parse().unwrap();
+            client.get(uri).await.expect("GET failed")
+        }
+    };
+    let get_a = tokio::spawn(get("a"));
+    let get_b = tokio::spawn(get("b"));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let send = |path: &'static str, body: &'static str| {
+        let client = client.clone();
+        async move {
+            let req = Request::post(format!("http://127.0.0.1:{}/{}", port, path))
+                .body(Body::from(body))
+                .unwrap();
+            client.request(req).await.expect("POST failed")
+        }
+    };
+    let (res_a, res_b) = tokio::join!(send("a", "hello a"), send("b", "hello b"));
+    assert!(res_a.status().is_success());
+    assert!(res_b.status().is_success());
+
+    let body_a = hyper::body::to_bytes(get_a.await.unwrap().into_body())
+        .await
+        .unwrap();
+    let body_b = hyper::body::to_bytes(get_b.await.unwrap().into_body())
+        .await
+        .unwrap();
+    assert_eq!(&body_a[..], b"hello a");
+    assert_eq!(&body_b[..], b"hello b");
+}
+
+#[tokio::test]
+async fn plain_http_rejects_h2_prior_knowledge_without_the_flag() {
+    let port = 18081;
+    let _server = spawn_server(port, false);
+    wait_for_listening(port).await;
+
+    let client = Client::builder()
+        .http2_only(true)
+        .build_http::<Body>();
+    let uri = format!("http://127.0.0.1:{}/a", port).parse().unwrap();
+    let result = client.get(uri).await;
+    assert!(
+        result.is_err(),
+        "h2 prior-knowledge request should fail against an HTTP/1.1-only listener"
+    );
+}
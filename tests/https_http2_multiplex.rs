@@ -0,0 +1,170 @@
+//! Checks that `--http2` ALPN-gates HTTP/2 on the TLS listener too: a
+//! self-signed cert is generated on the fly, several pipes run over
+//! one ALPN-negotiated h2 connection when the flag is on, and an h2
+//! client fails to negotiate anything but HTTP/1.1 when it's off.
+
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::{Body, Client, Request};
+use hyper_rustls::HttpsConnectorBuilder;
+
+struct ServerProcess(Child);
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+struct NoCertVerification;
+
+impl rustls::client::ServerCertVerifier for NoCertVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+// The server's self-signed cert/key, written once to a temp dir and
+// reused by both tests below.
+fn write_self_signed_cert(dir: &std::path::Path) -> (String, String) {
+    static WRITE_ONCE: std::sync::Once = std::sync::Once::new();
+    let crt_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+    WRITE_ONCE.call_once(|| {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        std::fs::write(&crt_path, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+    });
+    (
+        crt_path.to_str().unwrap().to_owned(),
+        key_path.to_str().unwrap().to_owned(),
+    )
+}
+
+fn spawn_server(https_port: u16, crt_path: &str, key_path: &str, http2: bool) -> ServerProcess {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_piping-server"));
+    cmd.arg("--http-port")
+        .arg("0")
+        .arg("--enable-https")
+        .arg("--https-port")
+        .arg(https_port.to_string())
+        .arg("--crt-path")
+        .arg(crt_path)
+        .arg("--key-path")
+        .arg(key_path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    if http2 {
+        cmd.arg("--http2");
+    }
+    ServerProcess(cmd.spawn().expect("failed to spawn piping-server"))
+}
+
+fn insecure_tls_config() -> rustls::ClientConfig {
+    rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(NoCertVerification))
+        .with_no_client_auth()
+}
+
+async fn wait_for_listening(port: u16) {
+    for _ in 0..50 {
+        if tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .is_ok()
+        {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("server never started listening on port {}", port);
+}
+
+#[tokio::test]
+async fn multiplexes_several_pipes_over_one_alpn_negotiated_h2_connection() {
+    let dir = tempfile_dir();
+    let (crt_path, key_path) = write_self_signed_cert(&dir);
+    let port = 18443;
+    let _server = spawn_server(port, &crt_path, &key_path, true);
+    wait_for_listening(port).await;
+
+    let connector = HttpsConnectorBuilder::new()
+        .with_tls_config(insecure_tls_config())
+        .https_only()
+        .enable_http2()
+        .build();
+    let client = Client::builder().http2_only(true).build(connector);
+
+    let get = |path: &'static str| {
+        let client = client.clone();
+        async move {
+            let uri = format!("https://127.0.0.1:{}/{}", port, path).parse().unwrap();
+            client.get(uri).await.expect("GET failed")
+        }
+    };
+    let get_a = tokio::spawn(get("a"));
+    let get_b = tokio::spawn(get("b"));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let send = |path: &'static str, body: &'static str| {
+        let client = client.clone();
+        async move {
+            let req = Request::post(format!("https://127.0.0.1:{}/{}", port, path))
+                .body(Body::from(body))
+                .unwrap();
+            client.request(req).await.expect("POST failed")
+        }
+    };
+    let (res_a, res_b) = tokio::join!(send("a", "hello a"), send("b", "hello b"));
+    assert!(res_a.status().is_success());
+    assert!(res_b.status().is_success());
+
+    let body_a = hyper::body::to_bytes(get_a.await.unwrap().into_body())
+        .await
+        .unwrap();
+    let body_b = hyper::body::to_bytes(get_b.await.unwrap().into_body())
+        .await
+        .unwrap();
+    assert_eq!(&body_a[..], b"hello a");
+    assert_eq!(&body_b[..], b"hello b");
+}
+
+#[tokio::test]
+async fn https_without_the_flag_never_negotiates_h2() {
+    let dir = tempfile_dir();
+    let (crt_path, key_path) = write_self_signed_cert(&dir);
+    let port = 18444;
+    let _server = spawn_server(port, &crt_path, &key_path, false);
+    wait_for_listening(port).await;
+
+    let connector = HttpsConnectorBuilder::new()
+        .with_tls_config(insecure_tls_config())
+        .https_only()
+        .enable_http2()
+        .build();
+    let client = Client::builder().http2_only(true).build(connector);
+    let uri = format!("https://127.0.0.1:{}/a", port).parse().unwrap();
+    let result = client.get(uri).await;
+    assert!(
+        result.is_err(),
+        "an h2-only client should fail to negotiate HTTP/2 when --http2 is off, since `h2` is \
+         never offered in ALPN"
+    );
+}
+
+fn tempfile_dir() -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("piping-server-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}